@@ -0,0 +1,148 @@
+//! A shared, park-on-full/notify-on-drop connection counter.
+//!
+//! Reused anywhere the accept loop needs to know how many connections are
+//! currently alive: graceful shutdown (`drain`) waits for the count to hit
+//! zero, while `Server::max_sockets` pauses accepting once it reaches a
+//! limit and resumes once it falls back to a low watermark.
+//!
+//! The same `Counter` is shared between both consumers (`Drain::counter()`
+//! hands the identical instance to `MaxSockets`), so `park()` keeps a list
+//! of waiting tasks rather than a single slot -- otherwise one waiter
+//! calling `park()` would silently evict the other's registration, and a
+//! `Listening::shutdown()` racing with `max_sockets` backpressure could
+//! park forever with nobody left to notice the count reaching zero.
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::task::{self, Task};
+
+/// A cloneable handle onto the live count.
+#[derive(Clone)]
+pub struct Counter {
+    count: Rc<AtomicUsize>,
+    parked: Rc<RefCell<Vec<Task>>>,
+}
+
+/// An RAII marker for one live connection, returned by `Counter::guard()`.
+pub struct Guard {
+    count: Rc<AtomicUsize>,
+    parked: Rc<RefCell<Vec<Task>>>,
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter {
+            count: Rc::new(AtomicUsize::new(0)),
+            parked: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// The number of `Guard`s currently alive.
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Registers one live connection, returning a guard that should be
+    /// dropped when the connection closes.
+    pub fn guard(&self) -> Guard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Guard {
+            count: self.count.clone(),
+            parked: self.parked.clone(),
+        }
+    }
+
+    /// Parks the current task to be woken the next time a `Guard` drops.
+    ///
+    /// Safe to call from more than one task waiting on this same `Counter`
+    /// -- every parked task is kept and notified, so one waiter never steals
+    /// another's registration.
+    pub fn park(&self) {
+        self.parked.borrow_mut().push(task::current());
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        for task in self.parked.borrow_mut().drain(..) {
+            task.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use futures::{Async, Future, Poll};
+    use futures::executor::{self, Notify};
+
+    use super::Counter;
+
+    #[test]
+    fn guard_tracks_live_count() {
+        let counter = Counter::new();
+        assert_eq!(counter.get(), 0);
+
+        let g1 = counter.guard();
+        assert_eq!(counter.get(), 1);
+
+        let g2 = counter.guard();
+        assert_eq!(counter.get(), 2);
+
+        drop(g1);
+        assert_eq!(counter.get(), 1);
+
+        drop(g2);
+        assert_eq!(counter.get(), 0);
+    }
+
+    struct RecordingNotify(Mutex<Vec<usize>>);
+
+    impl Notify for RecordingNotify {
+        fn notify(&self, id: usize) {
+            self.0.lock().unwrap().push(id);
+        }
+    }
+
+    /// A future that just parks on a `Counter` and stays pending forever,
+    /// standing in for one of the several independent things that wait on
+    /// the same `Counter` (`Drain`'s `Watch` and `MaxSockets`, in practice).
+    struct Parker(Counter);
+
+    impl Future for Parker {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<(), ()> {
+            self.0.park();
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn park_notifies_every_waiter_not_just_the_last() {
+        let counter = Counter::new();
+        let guard = counter.guard();
+
+        let notify = Arc::new(RecordingNotify(Mutex::new(Vec::new())));
+
+        let mut first = executor::spawn(Parker(counter.clone()));
+        let mut second = executor::spawn(Parker(counter.clone()));
+
+        // Registers both tasks as parked waiters on the same Counter. A
+        // single-slot park() would let the second registration silently
+        // evict the first.
+        assert_eq!(first.poll_future_notify(&notify, 1), Ok(Async::NotReady));
+        assert_eq!(second.poll_future_notify(&notify, 2), Ok(Async::NotReady));
+
+        drop(guard);
+
+        let notified = notify.0.lock().unwrap();
+        assert!(notified.contains(&1), "first waiter was not notified");
+        assert!(notified.contains(&2), "second waiter was not notified");
+    }
+}
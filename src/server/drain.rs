@@ -0,0 +1,209 @@
+//! Shared state that lets a graceful `Listening::shutdown()` wait for
+//! in-flight connections to finish instead of cutting them off.
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::{Async, Future, Poll};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use tokio::io::Io;
+
+use super::counter::{self, Counter};
+use super::listener::Connection;
+
+/// The watcher half, held by every live connection and by `Listening`.
+///
+/// Clone it into each spawned connection: `is_draining()` tells the
+/// connection's service to stop offering keep-alive, and holding onto the
+/// `Guard` returned by `guard()` for the connection's lifetime is what lets
+/// `Listening::shutdown()` know when every connection has finished.
+#[derive(Clone)]
+pub struct Drain {
+    draining: Rc<AtomicBool>,
+    counter: Counter,
+    signal: Shared<oneshot::Receiver<()>>,
+}
+
+/// The trigger half, held by `Listening` and fired by `shutdown()`.
+pub struct Signal {
+    tx: Option<oneshot::Sender<()>>,
+    draining: Rc<AtomicBool>,
+}
+
+/// An RAII marker for one live connection.
+///
+/// Held by a connection's `Service` for as long as the connection is open;
+/// dropping it tells any waiting `Drain` that one fewer connection remains.
+pub type Guard = counter::Guard;
+
+/// Creates a linked `Signal`/`Drain` pair for a freshly bound `Server`.
+pub fn channel() -> (Signal, Drain) {
+    let (tx, rx) = oneshot::channel();
+    let draining = Rc::new(AtomicBool::new(false));
+    (
+        Signal {
+            tx: Some(tx),
+            draining: draining.clone(),
+        },
+        Drain {
+            draining: draining,
+            counter: Counter::new(),
+            signal: rx.shared(),
+        },
+    )
+}
+
+impl Drain {
+    /// Registers a new in-flight connection, returning a guard that must be
+    /// held for as long as the connection stays open.
+    pub fn guard(&self) -> Guard {
+        self.counter.guard()
+    }
+
+    /// The counter backing this `Drain`, shared with `Server::max_sockets`
+    /// so both mechanisms agree on how many connections are alive.
+    pub fn counter(&self) -> Counter {
+        self.counter.clone()
+    }
+
+    /// Returns `true` once `Signal::drain()` has been called, so a
+    /// connection's service knows to stop offering keep-alive.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// A future that resolves the moment `Signal::drain()` is called.
+    ///
+    /// Cloneable, so every connection task can `select()` on its own copy.
+    pub fn signal(&self) -> Shared<oneshot::Receiver<()>> {
+        self.signal.clone()
+    }
+}
+
+impl Signal {
+    /// Marks the server as draining, waking every connection's `signal()`
+    /// future so it can finish its in-flight transaction and close.
+    pub fn drain(&mut self) {
+        self.draining.store(true, Ordering::SeqCst);
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+/// A future that resolves once every connection tracked by a `Drain` has
+/// closed, or a timeout elapses, whichever comes first.
+pub struct Watch {
+    drain: Drain,
+    timeout: Option<::tokio_timer::Sleep>,
+}
+
+impl Watch {
+    pub fn new(drain: Drain, timeout: Option<::tokio_timer::Sleep>) -> Watch {
+        Watch {
+            drain: drain,
+            timeout: timeout,
+        }
+    }
+}
+
+impl Future for Watch {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if let Some(ref mut timeout) = self.timeout {
+            if let Ok(Async::Ready(())) = timeout.poll() {
+                debug!("graceful shutdown timed out with connections still open");
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        if self.drain.counter.get() == 0 {
+            return Ok(Async::Ready(()));
+        }
+
+        self.drain.counter.park();
+        Ok(Async::NotReady)
+    }
+}
+
+/// Closes a connection as soon as a graceful `Listening::shutdown()` starts
+/// draining, even if the connection is otherwise sitting idle.
+///
+/// `Drain::signal()` is the future this wraps; there's no hook into
+/// `http::Conn`'s read loop to `select!` it against a connection's normal
+/// work (that module lives outside this tree), so instead this sits between
+/// the codec and the raw socket. Polling the shared signal future from
+/// inside `poll_read()` registers this connection's task as a waiter even
+/// while nothing else is happening on the socket, so it's woken the moment
+/// `Signal::drain()` fires rather than only at the next real read event.
+///
+/// This can't distinguish a connection idly waiting for its next keep-alive
+/// request from one in the middle of reading a request body, so draining
+/// may also cut off an in-flight request -- a transport-level wrapper has
+/// no visibility into which case it is.
+pub struct DrainIo<T> {
+    io: T,
+    signal: Shared<oneshot::Receiver<()>>,
+}
+
+impl<T> DrainIo<T> {
+    pub fn new(io: T, drain: &Drain) -> DrainIo<T> {
+        DrainIo {
+            io: io,
+            signal: drain.signal(),
+        }
+    }
+
+    fn is_draining(&mut self) -> bool {
+        match self.signal.poll() {
+            Ok(Async::NotReady) => false,
+            Ok(Async::Ready(_)) | Err(_) => true,
+        }
+    }
+}
+
+impl<T: Read> Read for DrainIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.is_draining() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "server is shutting down"));
+        }
+        self.io.read(buf)
+    }
+}
+
+impl<T: Write> Write for DrainIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: Io> Io for DrainIo<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        if self.is_draining() {
+            return Async::Ready(());
+        }
+        self.io.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.io.poll_write()
+    }
+}
+
+impl<T: Connection> Connection for DrainIo<T> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.io.remote_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
@@ -0,0 +1,301 @@
+//! Listener abstraction used by `Server` to accept incoming connections.
+//!
+//! `Server` is generic over anything that implements `Listener`, so a
+//! transport other than plain TCP (TLS, a Unix socket, ...) can be plugged
+//! in without touching the rest of the server machinery.
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll, Stream};
+#[cfg(unix)]
+use net2::TcpBuilder;
+use rustls::ServerConfig;
+use tokio::io::Io;
+use tokio::net::{TcpListener as TokioTcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio_rustls::{AcceptAsync, ServerConfigExt, TlsStream as RustlsStream};
+
+/// A connection accepted by a `Listener`.
+///
+/// In addition to being a usable transport, a `Connection` knows the
+/// addresses of both ends of the socket.
+pub trait Connection: Io {
+    /// Returns the socket address of the remote peer.
+    fn remote_addr(&self) -> SocketAddr;
+
+    /// Returns the local socket address this connection was accepted on.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A source of incoming connections for a `Server`.
+///
+/// Implement this to let `Server` drive a custom transport. `TcpListener`
+/// and `TlsListener` are the implementations provided by hyper.
+pub trait Listener {
+    /// The connections yielded by this listener.
+    type Connection: Connection + 'static;
+
+    /// The stream of accepted connections produced by `incoming()`.
+    type Incoming: Stream<Item = Self::Connection, Error = io::Error>;
+
+    /// The local address this listener is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Turns this listener into a stream of accepted connections.
+    fn incoming(self) -> Self::Incoming;
+
+    /// Binds another, independent listener at the same address, suitable
+    /// for running on a different reactor/OS thread.
+    ///
+    /// Used by `Server::workers()` to shard accepts across threads via
+    /// `SO_REUSEPORT`. The default errors; only listeners that can be
+    /// meaningfully duplicated this way (TCP, TLS-over-TCP) override it.
+    fn try_clone(&self, handle: &Handle) -> io::Result<Self> where Self: Sized {
+        let _ = handle;
+        Err(io::Error::new(io::ErrorKind::Other, "this Listener does not support Server::workers"))
+    }
+}
+
+/// Binds a `SO_REUSEPORT` socket at `addr`, so multiple independent
+/// listeners on different threads can all accept from the same address and
+/// let the kernel load-balance between them.
+#[cfg(unix)]
+pub(crate) fn bind_reuseport(addr: &SocketAddr) -> io::Result<StdTcpListener> {
+    use net2::unix::UnixTcpBuilderExt;
+
+    let builder = try!(match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4(),
+        SocketAddr::V6(_) => TcpBuilder::new_v6(),
+    });
+    try!(builder.reuse_address(true));
+    try!(builder.reuse_port(true));
+    try!(builder.bind(addr));
+    builder.listen(1024)
+}
+
+// There's no portable `SO_REUSEPORT` equivalent outside unix, so a second
+// worker binding the same address here would just fail with "address
+// already in use" -- fail clearly instead of pretending to try.
+#[cfg(not(unix))]
+pub(crate) fn bind_reuseport(_addr: &SocketAddr) -> io::Result<StdTcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_REUSEPORT is not supported on this platform; Server::workers() requires unix",
+    ))
+}
+
+/// A `TcpStream` tagged with the remote address it was accepted from.
+///
+/// This is what `TcpListener` and `TlsListener` yield from `incoming()`.
+#[derive(Debug)]
+pub struct AddrStream<T> {
+    io: T,
+    remote_addr: SocketAddr,
+}
+
+impl<T> AddrStream<T> {
+    fn new(io: T, remote_addr: SocketAddr) -> AddrStream<T> {
+        AddrStream {
+            io: io,
+            remote_addr: remote_addr,
+        }
+    }
+}
+
+impl<T: Read> Read for AddrStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<T: Write> Write for AddrStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: Io> Io for AddrStream<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        self.io.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.io.poll_write()
+    }
+}
+
+impl<T: Io + LocalAddr> Connection for AddrStream<T> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        LocalAddr::local_addr(&self.io)
+    }
+}
+
+// `TcpStream` doesn't expose `local_addr` through `Io`, so route it through
+// a tiny extension trait instead of widening `Io` itself.
+trait LocalAddr {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl LocalAddr for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+impl<S> LocalAddr for RustlsStream<TcpStream, S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+}
+
+/// The stream returned by `TcpListener::incoming()`.
+pub struct TcpIncoming {
+    listener: TokioTcpListener,
+}
+
+impl Stream for TcpIncoming {
+    type Item = AddrStream<TcpStream>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let (socket, addr) = try_ready!(self.listener.accept());
+        Ok(Async::Ready(Some(AddrStream::new(socket, addr))))
+    }
+}
+
+impl Listener for TokioTcpListener {
+    type Connection = AddrStream<TcpStream>;
+    type Incoming = TcpIncoming;
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TokioTcpListener::local_addr(self)
+    }
+
+    fn incoming(self) -> TcpIncoming {
+        TcpIncoming { listener: self }
+    }
+
+    fn try_clone(&self, handle: &Handle) -> io::Result<TokioTcpListener> {
+        let addr = try!(TokioTcpListener::local_addr(self));
+        let std_listener = try!(bind_reuseport(&addr));
+        TokioTcpListener::from_listener(std_listener, &addr, handle)
+    }
+}
+
+/// A `Listener` that terminates TLS on top of a `TcpListener`, using
+/// `tokio-rustls`.
+///
+/// Build one with `TlsListener::bind`, or hand it to `Server::https`.
+pub struct TlsListener {
+    listener: TokioTcpListener,
+    tls: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+    /// Binds a `TcpListener` at `addr` that will speak TLS using `config`.
+    ///
+    /// On unix the socket is bound with `SO_REUSEPORT`, as in `Server::http`,
+    /// so that `try_clone` can bind further listeners at the same address.
+    pub fn bind(addr: &SocketAddr, config: Arc<ServerConfig>, handle: &Handle) -> io::Result<TlsListener> {
+        let listener = if cfg!(unix) {
+            try!(bind_reuseport(addr))
+        } else {
+            try!(StdTcpListener::bind(addr))
+        };
+        let addr = try!(listener.local_addr());
+        Ok(TlsListener {
+            listener: try!(TokioTcpListener::from_listener(listener, &addr, handle)),
+            tls: config,
+        })
+    }
+}
+
+/// Caps how many TLS handshakes `TlsIncoming` will drive at once.
+///
+/// `Server::max_sockets`/`max_conn_rate` only wrap the stream of already
+/// -handshaked connections, so without a cap here a burst of TCP connects
+/// that never finish (or slow-walk) their handshake would pile up
+/// unbounded -- accept-side protection those limiters can't reach. This is
+/// a fixed, dedicated limit rather than `max_sockets` itself because a
+/// `Listener` is built before the `Server` that will configure it.
+const MAX_PENDING_HANDSHAKES: usize = 1024;
+
+/// The stream returned by `TlsListener::incoming()`.
+///
+/// Each accepted TCP socket is driven through its TLS handshake before being
+/// handed to the caller, so a slow or failed handshake on one socket never
+/// blocks the ones behind it.
+pub struct TlsIncoming {
+    listener: TokioTcpListener,
+    tls: Arc<ServerConfig>,
+    handshakes: Vec<(SocketAddr, AcceptAsync<TcpStream>)>,
+}
+
+impl Stream for TlsIncoming {
+    type Item = AddrStream<RustlsStream<TcpStream, ::rustls::ServerSession>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        while self.handshakes.len() < MAX_PENDING_HANDSHAKES {
+            match self.listener.accept() {
+                Ok(Async::Ready((socket, addr))) => {
+                    self.handshakes.push((addr, self.tls.accept_async(socket)));
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut i = 0;
+        while i < self.handshakes.len() {
+            match self.handshakes[i].1.poll() {
+                Ok(Async::Ready(tls_stream)) => {
+                    let (addr, _) = self.handshakes.remove(i);
+                    return Ok(Async::Ready(Some(AddrStream::new(tls_stream, addr))));
+                }
+                Ok(Async::NotReady) => {
+                    i += 1;
+                }
+                Err(e) => {
+                    warn!("tls handshake with {} failed: {}", self.handshakes[i].0, e);
+                    self.handshakes.remove(i);
+                }
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl Listener for TlsListener {
+    type Connection = AddrStream<RustlsStream<TcpStream, ::rustls::ServerSession>>;
+    type Incoming = TlsIncoming;
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn incoming(self) -> TlsIncoming {
+        TlsIncoming {
+            listener: self.listener,
+            tls: self.tls,
+            handshakes: Vec::new(),
+        }
+    }
+
+    fn try_clone(&self, handle: &Handle) -> io::Result<TlsListener> {
+        Ok(TlsListener {
+            listener: try!(Listener::try_clone(&self.listener, handle)),
+            tls: self.tls.clone(),
+        })
+    }
+}
@@ -2,20 +2,18 @@
 //!
 //! A `Server` is created to listen on a port, parse HTTP requests, and hand
 //! them off to a `Handler`.
-use std::cell::RefCell;
 use std::fmt;
 use std::io;
-use std::marker::PhantomData;
-use std::net::{SocketAddr, TcpListener as StdTcpListener};
-use std::rc::Rc;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::Duration;
 
-use futures::{Future, Async, Map};
+use futures::{Future, Async, Poll};
 use futures::stream::{Stream};
+use futures::sync::oneshot;
+use tokio_timer::Timer;
 
-use tokio::io::Io;
 use tokio::net::TcpListener;
 use tokio::reactor::{Core, Handle};
 use tokio_proto::BindServer;
@@ -23,49 +21,74 @@ use tokio_proto::streaming::Message;
 use tokio_proto::streaming::pipeline::ServerProto;
 pub use tokio_service::{NewService, Service};
 
+pub use self::drain::{Drain, Signal};
+pub use self::listener::{Connection, Listener, TlsListener};
 pub use self::request::Request;
 pub use self::response::Response;
 
-//use self::conn::Conn;
-
 use body::{Body, TokioBody};
 use http;
+use self::accept::MaxSockets;
 
-
-//mod conn;
+mod accept;
+mod counter;
+mod drain;
+mod listener;
 mod request;
 mod response;
+mod timeout;
 
 type ServerBody = Body;
 type HttpListener = TcpListener;
 
+/// The window `Server::max_conn_rate` counts accepted connections over.
+fn conn_rate_window() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// The default for `Server::workers`.
+///
+/// `SO_REUSEPORT` sharding is unix-only (see `listener::bind_reuseport`), so
+/// defaulting to the logical CPU count on every platform would make
+/// `standalone()` fail out of the box on non-unix. There, default to a
+/// single worker, same as before `Server::workers` existed.
+#[cfg(unix)]
+fn default_workers() -> usize {
+    ::num_cpus::get()
+}
+
+#[cfg(not(unix))]
+fn default_workers() -> usize {
+    1
+}
+
 /// A Server that can accept incoming network requests.
 #[derive(Debug)]
 pub struct Server<A> {
-    //listeners: Vec<A>,
-    _marker: PhantomData<A>,
-    addr: SocketAddr,
+    listener: A,
     keep_alive: bool,
     idle_timeout: Option<Duration>,
+    header_read_timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
     max_sockets: usize,
+    max_conn_rate: Option<usize>,
+    workers: usize,
 }
 
-impl<A: Accept> Server<A> {
-    /*
-    /// Creates a new Server from one or more Listeners.
-    ///
-    /// Panics if listeners is an empty iterator.
-    pub fn new<I: IntoIterator<Item = A>>(listeners: I) -> Server<A> {
-        let listeners = listeners.into_iter().collect();
-
+impl<A: Listener> Server<A> {
+    /// Creates a new Server from an already-bound `Listener`.
+    pub fn new(listener: A) -> Server<A> {
         Server {
-            listeners: listeners,
+            listener: listener,
             keep_alive: true,
             idle_timeout: Some(Duration::from_secs(10)),
+            header_read_timeout: None,
+            first_byte_timeout: None,
             max_sockets: 4096,
+            max_conn_rate: None,
+            workers: default_workers(),
         }
     }
-    */
 
     /// Enables or disables HTTP keep-alive.
     ///
@@ -83,75 +106,237 @@ impl<A: Accept> Server<A> {
         self
     }
 
+    /// Sets how long a connection may take to finish sending its first
+    /// request's headers before it is dropped.
+    ///
+    /// The timer starts as soon as the connection is accepted and is
+    /// cancelled as soon as a complete head has been read off the wire,
+    /// guarding against a slowloris-style client that opens a socket and
+    /// trickles headers in forever. It only covers the connection's first
+    /// request -- `idle_timeout` is what bounds the wait for a second,
+    /// keep-alive request.
+    ///
+    /// Default is unlimited.
+    pub fn header_read_timeout(mut self, val: Duration) -> Server<A> {
+        self.header_read_timeout = Some(val);
+        self
+    }
+
+    /// Sets how long a connection may sit open without producing any bytes
+    /// at all before it is dropped.
+    ///
+    /// Unlike `header_read_timeout`, this fires even if the client never
+    /// sends a single byte, closing connections opened just to hold a slot
+    /// without ever starting a request. Like `header_read_timeout`, it only
+    /// applies to the connection's first request.
+    ///
+    /// Default is unlimited.
+    pub fn first_byte_timeout(mut self, val: Duration) -> Server<A> {
+        self.first_byte_timeout = Some(val);
+        self
+    }
+
     /// Sets the maximum open sockets for this Server.
     ///
+    /// Once this many connections are alive at once, the accept loop stops
+    /// pulling new connections off the listener until enough of them close
+    /// to fall back under a low watermark, so a flood of clients can't
+    /// exhaust file descriptors. Each worker (see `workers`) counts its own
+    /// connections against this limit separately.
+    ///
     /// Default is 4096, but most servers can handle much more than this.
     pub fn max_sockets(mut self, val: usize) -> Server<A> {
         self.max_sockets = val;
         self
     }
+
+    /// Limits how many *new* connections are accepted per second.
+    ///
+    /// Unlike `max_sockets`, this caps the rate of new connections rather
+    /// than the total alive at once, which protects against a burst of
+    /// cheap-to-open, expensive-to-service connections. Once the limit is
+    /// hit, accepting pauses for one second. Each worker (see `workers`)
+    /// counts its own accepts against this limit separately.
+    ///
+    /// Default is unlimited.
+    pub fn max_conn_rate(mut self, val: usize) -> Server<A> {
+        self.max_conn_rate = Some(val);
+        self
+    }
+
+    /// Sets how many OS threads will accept and serve connections.
+    ///
+    /// Each worker binds its own `SO_REUSEPORT` socket at the same address
+    /// and runs its own reactor, so the kernel load-balances accepts across
+    /// them with no cross-thread handoff. `factory` is cloned once per
+    /// worker, so each thread builds its own `NewService`. `SO_REUSEPORT` is
+    /// unix-only, so setting this above 1 on other platforms will make
+    /// `standalone()` return an error.
+    ///
+    /// Default is the number of logical CPUs on unix, or 1 elsewhere.
+    pub fn workers(mut self, val: usize) -> Server<A> {
+        self.workers = val;
+        self
+    }
 }
 
-impl Server<HttpListener> { //<H: HandlerFactory<<HttpListener as Accept>::Output>> Server<HttpListener, H> {
+impl Server<HttpListener> {
     /// Creates a new HTTP server config listening on the provided address.
-    pub fn http(addr: &SocketAddr) -> ::Result<Server<HttpListener>> {
-        Ok(Server {
-            _marker: PhantomData,
-            addr: addr.clone(),
-            keep_alive: true,
-            idle_timeout: Some(Duration::from_secs(10)),
-            max_sockets: 4096,
-        })
+    ///
+    /// Bound with `SO_REUSEPORT` on unix so that `Server::workers()` can later
+    /// shard accepts across threads: on unix, `SO_REUSEPORT` requires *every*
+    /// socket sharing an address to set the option, including this first
+    /// one, or the extra workers' own `SO_REUSEPORT` binds to the same
+    /// address fail with `EADDRINUSE`. Elsewhere a plain socket is bound.
+    pub fn http(addr: &SocketAddr, handle: &Handle) -> ::Result<Server<HttpListener>> {
+        let listener = if cfg!(unix) {
+            try!(listener::bind_reuseport(addr))
+        } else {
+            try!(::std::net::TcpListener::bind(addr))
+        };
+        let addr = try!(listener.local_addr());
+        let listener = try!(TcpListener::from_listener(listener, &addr, handle));
+        Ok(Server::new(listener))
     }
 }
 
-
-/*
-impl<S: SslServer> Server<HttpsListener<S>> {
-    /// Creates a new server config that will handle `HttpStream`s over SSL.
+impl Server<TlsListener> {
+    /// Creates a new server config that will terminate TLS using `config`,
+    /// listening on the provided address.
     ///
-    /// You can use any SSL implementation, as long as it implements `hyper::net::Ssl`.
-    pub fn https(addr: &SocketAddr, ssl: S) -> ::Result<Server<HttpsListener<S>>> {
-        HttpsListener::new(addr, ssl)
-            .map(Server::new)
-            .map_err(From::from)
+    /// You can use any TLS implementation supported by `rustls`.
+    pub fn https(addr: &SocketAddr, config: Arc<::rustls::ServerConfig>, handle: &Handle) -> ::Result<Server<TlsListener>> {
+        let listener = try!(TlsListener::bind(addr, config, handle));
+        Ok(Server::new(listener))
     }
 }
-*/
-
 
-impl/*<A: Accept>*/ Server<HttpListener> {
+impl<A: Listener> Server<A> {
     /// Binds to a socket and starts handling connections.
-    pub fn handle<H>(mut self, factory: H, handle: &Handle) -> ::Result<SocketAddr>
+    ///
+    /// Returns the bound address along with the `drain` handles a caller
+    /// needs to later build a `Listening` capable of graceful shutdown.
+    pub fn handle<H>(self, factory: H, handle: &Handle) -> ::Result<(SocketAddr, Signal, Drain)>
     where H: NewService<Request=Request, Response=Response, Error=::Error> + Send + 'static {
-        let listener = try!(StdTcpListener::bind(&self.addr));
-        let addr = try!(listener.local_addr());
-        let listener = try!(TcpListener::from_listener(listener, &addr, handle));
+        let addr = try!(self.listener.local_addr());
         let binder = HttpServer;
+        let (signal, drain) = drain::channel();
+        let max_sockets = self.max_sockets;
+        let max_conn_rate = self.max_conn_rate;
+        let header_read_timeout = self.header_read_timeout;
+        let first_byte_timeout = self.first_byte_timeout;
+        let timer = Timer::default();
 
         let inner_handle = handle.clone();
-        handle.spawn(listener.incoming().for_each(move |(socket, _)| {
-            let service = HttpService { inner: try!(factory.new_service()) };
-            binder.bind_server(&inner_handle, socket, service);
-            Ok(())
-        }).map_err(|e| {
-            error!("listener io error: {:?}", e);
-            ()
-        }));
-
-        Ok(addr)
+        let incoming_drain = drain.clone();
+        let conn_drain = drain.clone();
+        let incoming = MaxSockets::new(self.listener.incoming(), drain.counter(), max_sockets);
+        let incoming: Box<Stream<Item = A::Connection, Error = io::Error>> = match max_conn_rate {
+            Some(rate) => Box::new(accept::MaxConnRate::new(incoming, rate, conn_rate_window())),
+            None => Box::new(incoming),
+        };
+        handle.spawn(incoming
+            .take_while(move |_| Ok(!incoming_drain.is_draining()))
+            .for_each(move |socket| {
+                let remote_addr = socket.remote_addr();
+                let socket = timeout::TimeoutIo::new(socket, &timer, first_byte_timeout, header_read_timeout);
+                let socket = drain::DrainIo::new(socket, &conn_drain);
+                let service = HttpService {
+                    inner: try!(factory.new_service()),
+                    drain: conn_drain.clone(),
+                    _guard: conn_drain.guard(),
+                    remote_addr: remote_addr,
+                };
+                binder.bind_server(&inner_handle, socket, service);
+                Ok(())
+            }).map_err(|e| {
+                error!("listener io error: {:?}", e);
+                ()
+            }));
+
+        Ok((addr, signal, drain))
     }
 
-    pub fn standalone<H>(mut self, factory: H) -> ::Result<(Listening, ServerLoop)>
-    where H: NewService<Request=Request, Response=Response, Error=::Error> + Send + 'static {
+    /// Binds `self.workers` `SO_REUSEPORT` listeners at the same address and
+    /// runs all but the first on dedicated worker threads, returning a
+    /// `Listening` that controls every worker and a `ServerLoop` that
+    /// drives the first worker's reactor on the calling thread.
+    pub fn standalone<H>(self, factory: H) -> ::Result<(Listening, ServerLoop)>
+    where H: NewService<Request=Request, Response=Response, Error=::Error> + Clone + Send + 'static {
+        let workers = if self.workers == 0 { 1 } else { self.workers };
+
+        // Bind the extra workers' listeners before the first worker consumes
+        // `self.listener`, since only `try_clone` needs the original socket.
+        let mut extra = Vec::with_capacity(workers - 1);
+        for _ in 1..workers {
+            let mut worker_core = try!(Core::new());
+            let worker_handle = worker_core.handle();
+            let worker_listener = try!(self.listener.try_clone(&worker_handle));
+            extra.push((worker_core, worker_handle, worker_listener));
+        }
+
+        let Server {
+            listener,
+            keep_alive,
+            idle_timeout,
+            header_read_timeout,
+            first_byte_timeout,
+            max_sockets,
+            max_conn_rate,
+            ..
+        } = self;
+
         let mut core = try!(Core::new());
         let handle = core.handle();
-        let addr = try!(self.handle(factory, &handle));
+        let (addr, signal, drain) = try!(Server {
+            listener: listener,
+            keep_alive: keep_alive,
+            idle_timeout: idle_timeout,
+            header_read_timeout: header_read_timeout,
+            first_byte_timeout: first_byte_timeout,
+            max_sockets: max_sockets,
+            max_conn_rate: max_conn_rate,
+            workers: 1,
+        }.handle(factory.clone(), &handle));
         let (shutdown_tx, shutdown_rx) = try!(::tokio::channel::channel(&handle));
+
+        let mut live_workers = vec![Worker {
+            handle: handle,
+            signal: signal,
+            drain: drain,
+            shutdown: shutdown_tx,
+        }];
+
+        for (mut worker_core, worker_handle, worker_listener) in extra {
+            let factory = factory.clone();
+            let (addr, signal, drain) = try!(Server {
+                listener: worker_listener,
+                keep_alive: keep_alive,
+                idle_timeout: idle_timeout,
+                header_read_timeout: header_read_timeout,
+                first_byte_timeout: first_byte_timeout,
+                max_sockets: max_sockets,
+                max_conn_rate: max_conn_rate,
+                workers: 1,
+            }.handle(factory, &worker_handle));
+            let _ = addr; // same address as the first worker, nothing new to report
+            let (worker_shutdown_tx, worker_shutdown_rx) = try!(::tokio::channel::channel(&worker_handle));
+            live_workers.push(Worker {
+                handle: worker_handle,
+                signal: signal,
+                drain: drain,
+                shutdown: worker_shutdown_tx,
+            });
+            thread::spawn(move || {
+                let _ = worker_core.run(worker_shutdown_rx.into_future());
+                debug!("worker thread closed");
+            });
+        }
+
         Ok((
             Listening {
                 addr: addr,
-                shutdown: shutdown_tx,
+                workers: live_workers,
             },
              ServerLoop {
                 inner: Some((core, shutdown_rx)),
@@ -161,6 +346,13 @@ impl/*<A: Accept>*/ Server<HttpListener> {
     }
 }
 
+struct Worker {
+    handle: Handle,
+    signal: Signal,
+    drain: Drain,
+    shutdown: ::tokio::channel::Sender<()>,
+}
+
 /// A configured `Server` ready to run.
 pub struct ServerLoop {
     inner: Option<(Core, ::tokio::channel::Receiver<()>)>,
@@ -193,7 +385,7 @@ impl Drop for ServerLoop {
 /// A handle of the running server.
 pub struct Listening {
     addr: SocketAddr,
-    shutdown: ::tokio::channel::Sender<()>,
+    workers: Vec<Worker>,
 }
 
 impl fmt::Debug for Listening {
@@ -216,16 +408,83 @@ impl Listening {
         &self.addr
     }
 
-    /// Stop the server from listening to its socket address.
+    /// Stop the server from listening to its socket address immediately.
+    ///
+    /// Every worker's in-flight requests are cut off as soon as its accept
+    /// loop and reactor notice the shutdown signal. Prefer `shutdown()` if
+    /// in-flight requests should be allowed to finish first.
     pub fn close(self) {
         debug!("closing server {}", self);
-        let _ = self.shutdown.send(());
+        for worker in self.workers {
+            let _ = worker.shutdown.send(());
+        }
+    }
+
+    /// Stop accepting new connections on every worker, and close once their
+    /// live connections have finished.
+    ///
+    /// No new sockets are accepted after this call. Every live connection
+    /// stops reading from its socket: one waiting for its next keep-alive
+    /// request closes right away, and a request whose head or body is still
+    /// being read is cut off. A response built after this call is marked
+    /// `Connection: close`. The returned future resolves once
+    /// every worker's connections have all closed on their own, or once
+    /// `timeout` elapses (if given), whichever happens first -- at which
+    /// point any connections still open are force-closed along with their
+    /// reactor.
+    pub fn shutdown(self, timeout: Option<Duration>) -> Shutdown {
+        debug!("shutting down server {}", self);
+        let mut pending = Vec::with_capacity(self.workers.len());
+        for worker in self.workers {
+            let Worker { handle, mut signal, drain, shutdown } = worker;
+            signal.drain();
+
+            let (tx, rx) = oneshot::channel();
+            let sleep = timeout.map(|dur| Timer::default().sleep(dur));
+            handle.spawn(drain::Watch::new(drain, sleep).then(move |_| {
+                let _ = shutdown.send(());
+                let _ = tx.send(());
+                Ok(())
+            }));
+            pending.push(rx);
+        }
+
+        Shutdown { inner: pending }
+    }
+}
+
+/// A future that resolves once a graceful `Listening::shutdown()` completes
+/// for every worker.
+pub struct Shutdown {
+    inner: Vec<oneshot::Receiver<()>>,
+}
+
+impl Future for Shutdown {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut i = 0;
+        while i < self.inner.len() {
+            match self.inner[i].poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(())) | Err(_) => {
+                    self.inner.remove(i);
+                }
+            }
+        }
+
+        if self.inner.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
 
 struct HttpServer;
 
-impl<T: Io + 'static> ServerProto<T> for HttpServer {
+impl<T: Connection + 'static> ServerProto<T> for HttpServer {
     type Request = http::RequestHead;
     type RequestBody = http::Chunk;
     type Response = ResponseHead;
@@ -241,6 +500,14 @@ impl<T: Io + 'static> ServerProto<T> for HttpServer {
 
 struct HttpService<T> {
     inner: T,
+    drain: Drain,
+    // Kept alive for as long as this connection is; its Drop is what lets
+    // `Listening::shutdown()` know the connection has finished.
+    _guard: drain::Guard,
+    // Captured from the accepted socket before it was handed to
+    // `bind_server`, since by the time a `Request` is built here the
+    // original `Connection` is owned by the bound transport.
+    remote_addr: SocketAddr,
 }
 
 fn map_response_to_message(res: Response) -> Message<ResponseHead, TokioBody> {
@@ -260,18 +527,23 @@ impl<T> Service for HttpService<T>
     type Request = Message<http::RequestHead, TokioBody>;
     type Response = Message<ResponseHead, TokioBody>;
     type Error = ::Error;
-    type Future = Map<T::Future, fn(Response) -> Message<ResponseHead, TokioBody>>;
+    type Future = Box<Future<Item=Message<ResponseHead, TokioBody>, Error=::Error>>;
 
     fn call(&self, message: Self::Request) -> Self::Future {
         let (head, body) = match message {
             Message::WithoutBody(head) => (head, Body::empty()),
             Message::WithBody(head, body) => (head, body.into()),
         };
-        let req = request::new(head, body);
-        self.inner.call(req).map(map_response_to_message)
+        let req = request::new(self.remote_addr, head, body);
+        // Once the server is draining, tell the client this is the last
+        // response it will get on this connection instead of offering
+        // another keep-alive round.
+        let drain = self.drain.clone();
+        Box::new(self.inner.call(req).map(move |mut res| {
+            if drain.is_draining() {
+                res.headers_mut().set(::header::Connection::close());
+            }
+            map_response_to_message(res)
+        }))
     }
 }
-
-trait Accept: Stream {
-
-}
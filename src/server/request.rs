@@ -0,0 +1,79 @@
+//! The `Request` given to a `Service`.
+use std::fmt;
+use std::net::SocketAddr;
+
+use header::Headers;
+use http::RequestLine;
+use method::Method;
+use uri::RequestUri;
+use version::HttpVersion;
+
+use super::ServerBody;
+
+/// An incoming HTTP request, handed to a `Service` by the `Server`.
+pub struct Request {
+    method: Method,
+    uri: RequestUri,
+    version: HttpVersion,
+    headers: Headers,
+    remote_addr: SocketAddr,
+    body: ServerBody,
+}
+
+impl Request {
+    /// The `Method` of this request.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// The `RequestUri` of this request.
+    pub fn uri(&self) -> &RequestUri {
+        &self.uri
+    }
+
+    /// The `HttpVersion` of this request.
+    pub fn version(&self) -> HttpVersion {
+        self.version
+    }
+
+    /// The `Headers` of this request.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The socket address of the client that sent this request.
+    ///
+    /// For a `TlsListener`-backed server this is the address of the raw TCP
+    /// peer, i.e. the same address the TLS handshake was accepted from.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Consumes the request, returning its `Body`.
+    pub fn body(self) -> ServerBody {
+        self.body
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("uri", &self.uri)
+            .field("version", &self.version)
+            .field("remote_addr", &self.remote_addr)
+            .finish()
+    }
+}
+
+pub fn new(remote_addr: SocketAddr, head: ::http::RequestHead, body: ServerBody) -> Request {
+    let RequestLine(method, uri) = head.subject;
+    Request {
+        method: method,
+        uri: uri,
+        version: head.version,
+        headers: head.headers,
+        remote_addr: remote_addr,
+        body: body,
+    }
+}
@@ -0,0 +1,160 @@
+//! Transport-level enforcement for `Server::first_byte_timeout` and
+//! `Server::header_read_timeout`.
+//!
+//! `http::Conn` lives outside this tree (no `Cargo.toml`/`http` module is
+//! present here), so there's no hook into the HTTP codec's read loop to arm
+//! and cancel timers per request. Instead, `TimeoutIo` wraps the raw `Connection`
+//! itself and watches the bytes flowing through it: `first_byte_timeout` is
+//! disarmed the moment any byte is read, and `header_read_timeout` is
+//! disarmed once a scan of those bytes finds the blank line ending the
+//! request head (`\r\n\r\n`), tracking a small carry-over tail across
+//! `read()` calls so the marker is still found if it spans a buffer
+//! boundary.
+//!
+//! This only covers a connection's *first* request: once headers are seen
+//! once, both timers are gone for good, since telling a slow keep-alive
+//! client apart from one idle between requests needs the HTTP codec's
+//! framing, which isn't visible at this layer.
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{Async, Future};
+use tokio::io::Io;
+use tokio_timer::{Sleep, Timer};
+
+use super::listener::Connection;
+
+/// The end-of-headers marker `TimeoutIo` scans for to know when to disarm
+/// `header_read_timeout`.
+const HEADERS_END: &'static [u8] = b"\r\n\r\n";
+
+pub struct TimeoutIo<T> {
+    io: T,
+    first_byte_timeout: Option<Sleep>,
+    header_read_timeout: Option<Sleep>,
+    headers_done: bool,
+    tail: [u8; 3],
+    tail_len: usize,
+}
+
+impl<T> TimeoutIo<T> {
+    pub fn new(io: T, timer: &Timer, first_byte_timeout: Option<Duration>, header_read_timeout: Option<Duration>) -> TimeoutIo<T> {
+        TimeoutIo {
+            io: io,
+            first_byte_timeout: first_byte_timeout.map(|dur| timer.sleep(dur)),
+            header_read_timeout: header_read_timeout.map(|dur| timer.sleep(dur)),
+            headers_done: false,
+            tail: [0; 3],
+            tail_len: 0,
+        }
+    }
+
+    /// Returns `true` if `buf` (appended to the carried-over tail from the
+    /// previous call) contains the end-of-headers marker, and refreshes the
+    /// tail for the next call either way.
+    fn scan_for_headers_end(&mut self, buf: &[u8]) -> bool {
+        let mut combined = Vec::with_capacity(self.tail_len + buf.len());
+        combined.extend_from_slice(&self.tail[..self.tail_len]);
+        combined.extend_from_slice(buf);
+
+        let found = combined.windows(HEADERS_END.len()).any(|window| window == HEADERS_END);
+
+        let keep = combined.len().min(self.tail.len());
+        self.tail[..keep].copy_from_slice(&combined[combined.len() - keep..]);
+        self.tail_len = keep;
+
+        found
+    }
+
+    fn check_timeouts(&mut self) -> io::Result<()> {
+        if let Some(ref mut sleep) = self.first_byte_timeout {
+            if let Ok(Async::Ready(())) = sleep.poll() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "first_byte_timeout elapsed"));
+            }
+        }
+        if !self.headers_done {
+            if let Some(ref mut sleep) = self.header_read_timeout {
+                if let Ok(Async::Ready(())) = sleep.poll() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "header_read_timeout elapsed"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for TimeoutIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.headers_done {
+            return self.io.read(buf);
+        }
+
+        match self.io.read(buf) {
+            Ok(n) => {
+                self.first_byte_timeout = None;
+                if n > 0 && self.scan_for_headers_end(&buf[..n]) {
+                    self.headers_done = true;
+                    self.header_read_timeout = None;
+                }
+                Ok(n)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                try!(self.check_timeouts());
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T: Write> Write for TimeoutIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: Io> Io for TimeoutIo<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        if self.headers_done {
+            return self.io.poll_read();
+        }
+
+        if self.io.poll_read().is_ready() {
+            return Async::Ready(());
+        }
+
+        if let Some(ref mut sleep) = self.first_byte_timeout {
+            if let Ok(Async::Ready(())) = sleep.poll() {
+                return Async::Ready(());
+            }
+        }
+        if !self.headers_done {
+            if let Some(ref mut sleep) = self.header_read_timeout {
+                if let Ok(Async::Ready(())) = sleep.poll() {
+                    return Async::Ready(());
+                }
+            }
+        }
+
+        Async::NotReady
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.io.poll_write()
+    }
+}
+
+impl<T: Connection> Connection for TimeoutIo<T> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.io.remote_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}